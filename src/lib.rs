@@ -3,23 +3,142 @@
 use git2::{Commit, Oid, Repository};
 use git2::{DiffOptions, Error};
 use regex::Regex;
-use std::collections::HashMap;
+use serde::{Serialize, Serializer};
+use std::collections::{HashMap, HashSet};
 use std::str;
+use std::str::FromStr;
+use std::thread;
 use structopt::StructOpt;
 use time;
 use time::{Timespec, Tm};
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 struct Fix {
     message: String,
+    #[serde(serialize_with = "serialize_tm")]
     date: Tm,
     files: Vec<String>,
+    author: String,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct Spot {
     file: String,
     score: f64,
+    fixes: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AuthorScore {
+    author: String,
+    score: f64,
+}
+
+fn serialize_tm<S>(tm: &Tm, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.serialize_str(&tm.rfc3339().to_string())
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Format {
+    Text,
+    Json,
+    Csv,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            "csv" => Ok(Format::Csv),
+            _ => Err(format!("unknown format: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Color {
+    Green,
+    Red,
+}
+
+impl FromStr for Color {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "green" => Ok(Color::Green),
+            "red" => Ok(Color::Red),
+            _ => Err(format!("unknown color: {}", s)),
+        }
+    }
+}
+
+const GREEN_RAMP: [(u8, u8, u8); 5] = [
+    (14, 68, 41),
+    (0, 109, 50),
+    (38, 166, 65),
+    (57, 211, 83),
+    (86, 240, 110),
+];
+
+const RED_RAMP: [(u8, u8, u8); 5] = [
+    (68, 14, 14),
+    (109, 0, 0),
+    (166, 38, 38),
+    (211, 57, 57),
+    (240, 86, 86),
+];
+
+fn ramp_for(color: Color) -> &'static [(u8, u8, u8); 5] {
+    match color {
+        Color::Green => &GREEN_RAMP,
+        Color::Red => &RED_RAMP,
+    }
+}
+
+fn heatmap_level(score: f64, min: f64, max: f64) -> usize {
+    if max <= min {
+        return 0;
+    }
+    (((score - min) / (max - min)) * 4.0).round() as usize
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Sort {
+    Score,
+    File,
+    Fixes,
+}
+
+impl FromStr for Sort {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "score" => Ok(Sort::Score),
+            "file" => Ok(Sort::File),
+            "fixes" => Ok(Sort::Fixes),
+            _ => Err(format!("unknown sort key: {}", s)),
+        }
+    }
+}
+
+fn rank(spots: &mut Vec<Spot>, sort: Sort, top: Option<usize>) {
+    match sort {
+        Sort::Score => spots.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap()),
+        Sort::File => spots.sort_by(|a, b| a.file.cmp(&b.file)),
+        Sort::Fixes => spots.sort_by(|a, b| b.fixes.cmp(&a.fixes)),
+    }
+
+    if let Some(n) = top {
+        spots.truncate(n);
+    }
 }
 
 #[derive(StructOpt)]
@@ -28,8 +147,8 @@ pub struct Opts {
     /// path of repository
     repo: String,
     #[structopt(name = "branch", short = "b", long = "branch")]
-    /// branch to crawl
-    branch: Option<String>,
+    /// branch to crawl (may be given multiple times to scan and merge several branches)
+    branch: Vec<String>,
     #[structopt(name = "depth", short = "d", long = "depth")]
     /// depth of log crawl (integer)
     depth: Option<usize>,
@@ -42,14 +161,46 @@ pub struct Opts {
     #[structopt(name = "display-timestamps", long = "display-timestamps")]
     /// show timestamps of each identified fix commit
     display_timestamps: Option<bool>,
+    #[structopt(name = "format", short = "f", long = "format")]
+    /// output format: text, json, or csv
+    format: Option<Format>,
+    #[structopt(name = "color", long = "color")]
+    /// render hotspot scores as an ANSI heatmap using this palette: green or red
+    color: Option<Color>,
+    #[structopt(name = "since", long = "since")]
+    /// only crawl commits on or after this date (YYYY-MM-DD), defaults to one year ago
+    since: Option<String>,
+    #[structopt(name = "until", long = "until")]
+    /// only crawl commits on or before this date (YYYY-MM-DD)
+    until: Option<String>,
+    #[structopt(name = "jobs", short = "j", long = "jobs")]
+    /// number of worker threads to diff commits with, defaults to the CPU count
+    jobs: Option<usize>,
+    #[structopt(name = "by-author", long = "by-author")]
+    /// also print a "Risky authors" section attributing hotspot score by commit author
+    by_author: Option<bool>,
+    #[structopt(name = "top", long = "top")]
+    /// only show the N highest-ranked hotspots
+    top: Option<usize>,
+    #[structopt(name = "sort", long = "sort")]
+    /// hotspot ranking key: score, file, or fixes
+    sort: Option<Sort>,
 }
 
 struct Options {
     repo: String,
-    branch: String,
+    branches: Vec<String>,
     depth: Option<usize>,
     regex: Regex,
     display_timestamps: bool,
+    format: Format,
+    color: Option<Color>,
+    since: Timespec,
+    until: Timespec,
+    jobs: usize,
+    by_author: bool,
+    top: Option<usize>,
+    sort: Sort,
 }
 
 fn reg(args: &Opts) -> Result<Regex, regex::Error> {
@@ -72,35 +223,173 @@ fn reg_from_words(args: &Opts) -> Option<String> {
     }
 }
 
-fn scan(opts: &Options) -> Result<(Vec<Fix>, Vec<Spot>), Error> {
-    let repo = Repository::open(&opts.repo)?;
-    let obj = repo.revparse_single(opts.branch.as_str())?;
+fn parse_date(s: &str) -> Result<Timespec, time::ParseError> {
+    let tm = time::strptime(s, "%Y-%m-%d")?;
+    Ok(tm.to_timespec())
+}
 
-    let mut revwalk = repo.revwalk()?;
-    revwalk.set_sorting(git2::Sort::TOPOLOGICAL)?;
-    revwalk.push(obj.id())?;
+const SECONDS_PER_DAY: i64 = 86400;
+
+// `--until` is a calendar date, so "on or before this date" should include
+// every second of that day, not just the instant at midnight.
+fn parse_until_date(s: &str) -> Result<Timespec, time::ParseError> {
+    let mut t = parse_date(s)?;
+    t.sec += SECONDS_PER_DAY - 1;
+    Ok(t)
+}
+
+type ScanResult = Result<(Vec<Fix>, Vec<Spot>, Vec<AuthorScore>), Error>;
+
+fn scan(opts: &Options) -> ScanResult {
+    let repo = Repository::open(&opts.repo)?;
 
-    let f = |c: Result<Oid, Error>| {
-        let id = match c {
-            Ok(i) => i,
-            Err(err) => panic!("{:?}", err),
+    // Commits are seen at most once across branches so a fix found on two
+    // branches isn't double-weighted.
+    let mut seen: HashSet<Oid> = HashSet::new();
+
+    let mut matched: Vec<Oid> = Vec::new();
+    for branch in &opts.branches {
+        let obj = repo.revparse_single(branch.as_str())?;
+
+        let mut revwalk = repo.revwalk()?;
+        revwalk.set_sorting(git2::Sort::TOPOLOGICAL)?;
+        revwalk.push(obj.id())?;
+
+        let f = |c: Result<Oid, Error>| {
+            let id = match c {
+                Ok(i) => i,
+                Err(err) => panic!("{:?}", err),
+            };
+            let commit = match repo.find_commit(id) {
+                Ok(c) => c,
+                Err(err) => panic!("{:?}", err),
+            };
+            commit
         };
-        let commit = match repo.find_commit(id) {
-            Ok(c) => c,
-            Err(err) => panic!("{:?}", err),
+        let commits: Vec<Commit> = match opts.depth {
+            Some(d) => {
+                let t = revwalk.take(d);
+                t.map(f).collect()
+            }
+            _ => revwalk.map(f).collect(),
         };
-        commit
-    };
-    let commits: Vec<Commit> = match opts.depth {
-        Some(d) => {
-            let t = revwalk.take(d);
-            t.map(f).collect()
+
+        for commit in commits {
+            if !seen.insert(commit.id()) {
+                continue;
+            }
+
+            let lines = String::from_utf8_lossy(commit.message_bytes());
+            let mut lines = lines.lines();
+            let message = match lines.next() {
+                Some(l) => String::from(l),
+                _ => String::from(""),
+            };
+
+            let seconds = commit.time().seconds();
+            if seconds < opts.since.sec || seconds > opts.until.sec {
+                continue;
+            }
+
+            if !opts.regex.is_match(message.as_str()) {
+                continue;
+            }
+
+            matched.push(commit.id());
         }
-        _ => revwalk.map(f).collect(),
-    };
+    }
+
+    let fixes = diff_matched_commits(&opts.repo, &matched, opts.jobs)?;
+
+    if fixes.is_empty() {
+        return Ok((fixes, Vec::new(), Vec::new()));
+    }
+
+    let mut hotspots: HashMap<String, f64> = HashMap::new();
+    let mut fix_counts: HashMap<String, usize> = HashMap::new();
+    let current_time = time::now();
+    // Merging branches means the last fix processed isn't necessarily the
+    // oldest one overall, so find it explicitly rather than assuming order.
+    let oldest_fix_date = fixes
+        .iter()
+        .map(|f| f.date)
+        .min_by_key(|d| d.to_timespec().sec)
+        .unwrap();
+    let oldest_fix_date = &oldest_fix_date;
+    for fix in &fixes {
+        for file in &fix.files {
+            let t = diff(&current_time, oldest_fix_date, &fix.date);
+            let value = match hotspots.get(file.as_str()) {
+                Some(t) => t,
+                _ => &0.0,
+            }
+            .clone();
+            hotspots.insert(String::from(file), t + value);
+
+            *fix_counts.entry(file.clone()).or_insert(0) += 1;
+        }
+    }
+
+    let mut spots: Vec<Spot> = Vec::new();
+    for (file, &n) in hotspots.iter() {
+        spots.push(Spot {
+            fixes: *fix_counts.get(file.as_str()).unwrap_or(&0),
+            file: file.clone(),
+            score: n,
+        });
+    }
+    spots.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap());
+
+    let mut authors: HashMap<String, f64> = HashMap::new();
+    for fix in &fixes {
+        let t = diff(&current_time, oldest_fix_date, &fix.date);
+        *authors.entry(fix.author.clone()).or_insert(0.0) += t;
+    }
+
+    let mut author_scores: Vec<AuthorScore> = Vec::new();
+    for (author, &n) in authors.iter() {
+        author_scores.push(AuthorScore {
+            author: author.clone(),
+            score: n,
+        });
+    }
+    author_scores.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap());
+
+    Ok((fixes, spots, author_scores))
+}
+
+fn diff_matched_commits(repo_path: &str, ids: &[Oid], jobs: usize) -> Result<Vec<Fix>, Error> {
+    if ids.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let jobs = jobs.max(1);
+    let chunk_size = ids.len().div_ceil(jobs);
+    let handles: Vec<thread::JoinHandle<Result<Vec<Fix>, Error>>> = ids
+        .chunks(chunk_size)
+        .map(|chunk| {
+            let repo_path = repo_path.to_string();
+            let chunk = chunk.to_vec();
+            thread::spawn(move || diff_commits(&repo_path, &chunk))
+        })
+        .collect();
 
     let mut fixes: Vec<Fix> = Vec::new();
-    for commit in commits {
+    for handle in handles {
+        let chunk_fixes = handle.join().expect("diff worker thread panicked")?;
+        fixes.extend(chunk_fixes);
+    }
+    Ok(fixes)
+}
+
+fn diff_commits(repo_path: &str, ids: &[Oid]) -> Result<Vec<Fix>, Error> {
+    // Each worker opens its own handle since git2::Repository isn't Send.
+    let repo = Repository::open(repo_path)?;
+
+    let mut fixes = Vec::new();
+    for id in ids {
+        let commit = repo.find_commit(*id)?;
+
         let lines = String::from_utf8_lossy(commit.message_bytes());
         let mut lines = lines.lines();
         let message = match lines.next() {
@@ -108,9 +397,12 @@ fn scan(opts: &Options) -> Result<(Vec<Fix>, Vec<Spot>), Error> {
             _ => String::from(""),
         };
 
-        if !opts.regex.is_match(message.as_str()) {
-            continue;
-        }
+        let signature = commit.author();
+        let author = format!(
+            "{} <{}>",
+            signature.name().unwrap_or(""),
+            signature.email().unwrap_or("")
+        );
 
         let a = commit.parent(0)?;
         let a = a.tree()?;
@@ -140,34 +432,10 @@ fn scan(opts: &Options) -> Result<(Vec<Fix>, Vec<Spot>), Error> {
                 nsec: 0,
             }),
             files,
+            author,
         });
     }
-
-    let mut hotspots: HashMap<String, f64> = HashMap::new();
-    let current_time = time::now();
-    let oldest_fix_date = &fixes.last().unwrap().date;
-    for fix in &fixes {
-        for file in &fix.files {
-            let t = diff(&current_time, oldest_fix_date, &fix.date);
-            let value = match hotspots.get(file.as_str()) {
-                Some(t) => t,
-                _ => &0.0,
-            }
-            .clone();
-            hotspots.insert(String::from(file), t + value);
-        }
-    }
-
-    let mut spots: Vec<Spot> = Vec::new();
-    for (file, &n) in hotspots.iter() {
-        spots.push(Spot {
-            file: file.clone(),
-            score: n,
-        });
-    }
-    spots.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap());
-
-    Ok((fixes, spots))
+    Ok(fixes)
 }
 
 fn diff(current_time: &Tm, oldest_fix_date: &Tm, fix_date: &Tm) -> f64 {
@@ -186,28 +454,106 @@ fn diff(current_time: &Tm, oldest_fix_date: &Tm, fix_date: &Tm) -> f64 {
 pub fn run(args: &Opts) -> Result<(), Error> {
     let options = Options {
         repo: args.repo.clone(),
-        branch: args.branch.clone().unwrap_or("main".to_string()),
+        branches: if args.branch.is_empty() {
+            vec!["main".to_string()]
+        } else {
+            args.branch.clone()
+        },
         depth: args.depth.clone(),
         regex: reg(&args).unwrap(),
         display_timestamps: args.display_timestamps.unwrap_or(false),
+        format: args.format.unwrap_or(Format::Text),
+        color: args.color,
+        since: match &args.since {
+            Some(s) => parse_date(s).unwrap(),
+            _ => {
+                let mut t = time::now();
+                t.tm_year -= 1;
+                t.to_timespec()
+            }
+        },
+        until: match &args.until {
+            Some(s) => parse_until_date(s).unwrap(),
+            _ => time::now().to_timespec(),
+        },
+        jobs: args
+            .jobs
+            .unwrap_or_else(|| thread::available_parallelism().map(|n| n.get()).unwrap_or(1)),
+        by_author: args.by_author.unwrap_or(false),
+        top: args.top,
+        sort: args.sort.unwrap_or(Sort::Score),
     };
 
-    println!("Scanning {} repo", args.repo);
+    let (fixes, mut spots, author_scores) = scan(&options)?;
+    let total_hotspots = spots.len();
+    rank(&mut spots, options.sort, options.top);
+
+    match options.format {
+        Format::Json => print_json(&fixes, &spots),
+        Format::Csv => print_csv(&spots),
+        Format::Text => print_text(
+            &args.repo,
+            &fixes,
+            &spots,
+            total_hotspots,
+            &author_scores,
+            &PrintOptions {
+                display_timestamps: options.display_timestamps,
+                color: options.color,
+                by_author: options.by_author,
+            },
+        ),
+    }
+
+    return Ok(());
+}
+
+#[derive(Serialize)]
+struct Report<'a> {
+    fixes: &'a Vec<Fix>,
+    spots: &'a Vec<Spot>,
+}
+
+fn print_json(fixes: &Vec<Fix>, spots: &Vec<Spot>) {
+    let report = Report { fixes, spots };
+    println!("{}", serde_json::to_string_pretty(&report).unwrap());
+}
+
+fn print_csv(spots: &Vec<Spot>) {
+    println!("file,score,fixes");
+    for s in spots {
+        println!("{},{:.4},{}", s.file, s.score, s.fixes);
+    }
+}
+
+struct PrintOptions {
+    display_timestamps: bool,
+    color: Option<Color>,
+    by_author: bool,
+}
 
-    let (fixes, spots) = scan(&options)?;
+fn print_text(
+    repo: &str,
+    fixes: &Vec<Fix>,
+    spots: &Vec<Spot>,
+    total_hotspots: usize,
+    author_scores: &Vec<AuthorScore>,
+    opts: &PrintOptions,
+) {
+    println!("Scanning {} repo", repo);
 
     println!(
         "\tFound {} bugfix commits, with {} hotspots:",
         fixes.len(),
-        spots.len()
+        total_hotspots
     );
     println!();
 
     println!("\tFixes:");
-    for f in &fixes {
+    for f in fixes {
         let mut messages: Vec<String> = Vec::new();
         messages.push("\t\t-".to_string());
-        if options.display_timestamps {
+        if opts.display_timestamps {
             messages.push(format!("{} ", f.date.rfc3339()))
         }
         messages.push(format!("{}", f.message));
@@ -216,16 +562,51 @@ pub fn run(args: &Opts) -> Result<(), Error> {
 
     println!();
     println!("\tHotspots:");
-    for s in &spots {
-        println!("\t\t{:.*} - {}", 4, s.score, s.file);
+    match opts.color {
+        Some(c) => print_hotspots_heatmap(spots, c),
+        None => {
+            for s in spots {
+                println!("\t\t{:.*} - {}", 4, s.score, s.file);
+            }
+        }
     }
 
-    return Ok(());
+    if opts.by_author {
+        println!();
+        println!("\tRisky authors:");
+        for a in author_scores {
+            println!("\t\t{:.*} - {}", 4, a.score, a.author);
+        }
+    }
+}
+
+fn print_hotspots_heatmap(spots: &Vec<Spot>, color: Color) {
+    let ramp = ramp_for(color);
+    let min = spots
+        .iter()
+        .map(|s| s.score)
+        .fold(f64::INFINITY, f64::min);
+    let max = spots
+        .iter()
+        .map(|s| s.score)
+        .fold(f64::NEG_INFINITY, f64::max);
+
+    for s in spots {
+        let level = heatmap_level(s.score, min, max);
+        let (r, g, b) = ramp[level];
+        println!(
+            "\t\t{:.*} - \x1B[38;2;{};{};{}m{}\x1B[0m",
+            4, s.score, r, g, b, s.file
+        );
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{diff, reg, run, Opts};
+    use crate::{
+        diff, heatmap_level, parse_date, parse_until_date, rank, reg, run, Color, Format, Opts,
+        Sort, Spot,
+    };
     use std::env;
     use std::ops::Sub;
     use time::Duration;
@@ -236,11 +617,19 @@ mod tests {
 
         let opts = Opts {
             repo: repo_path,
-            branch: Some("main".to_string()),
+            branch: vec!["main".to_string()],
             depth: Some(200),
             words: None,
             regex: Some("\\[fix\\]".to_string()),
             display_timestamps: None,
+            format: None,
+            color: None,
+            since: None,
+            until: None,
+            jobs: None,
+            by_author: None,
+            top: None,
+            sort: None,
         };
 
         let ret = run(&opts);
@@ -261,12 +650,100 @@ mod tests {
     fn test_reg() {
         let ret = reg(&Opts {
             repo: "".to_string(),
-            branch: None,
+            branch: vec![],
             depth: None,
             words: Some("a,b,c".to_string()),
             regex: None,
             display_timestamps: None,
+            format: None,
+            color: None,
+            since: None,
+            until: None,
+            jobs: None,
+            by_author: None,
+            top: None,
+            sort: None,
         });
         assert_eq!(ret.unwrap().as_str(), "a|b|c");
     }
+
+    #[test]
+    fn test_heatmap_level() {
+        assert_eq!(heatmap_level(0.0, 0.0, 1.0), 0);
+        assert_eq!(heatmap_level(1.0, 0.0, 1.0), 4);
+        assert_eq!(heatmap_level(0.5, 0.0, 1.0), 2);
+        // max <= min (e.g. a single spot) must not divide by zero
+        assert_eq!(heatmap_level(0.5, 1.0, 1.0), 0);
+    }
+
+    #[test]
+    fn test_parse_date() {
+        let a = parse_date("2020-01-02").unwrap();
+        let b = parse_date("2020-01-03").unwrap();
+        assert_eq!(b.sec - a.sec, 86400);
+    }
+
+    #[test]
+    fn test_parse_until_date_includes_whole_day() {
+        let start_of_day = parse_date("2020-01-02").unwrap();
+        let end_of_day = parse_until_date("2020-01-02").unwrap();
+        assert_eq!(end_of_day.sec - start_of_day.sec, 86399);
+    }
+
+    #[test]
+    fn test_rank() {
+        let mut spots = vec![
+            Spot {
+                file: "b.rs".to_string(),
+                score: 0.5,
+                fixes: 2,
+            },
+            Spot {
+                file: "a.rs".to_string(),
+                score: 0.9,
+                fixes: 5,
+            },
+            Spot {
+                file: "c.rs".to_string(),
+                score: 0.1,
+                fixes: 9,
+            },
+        ];
+
+        rank(&mut spots, Sort::File, None);
+        let files: Vec<&str> = spots.iter().map(|s| s.file.as_str()).collect();
+        assert_eq!(files, vec!["a.rs", "b.rs", "c.rs"]);
+
+        rank(&mut spots, Sort::Fixes, None);
+        let files: Vec<&str> = spots.iter().map(|s| s.file.as_str()).collect();
+        assert_eq!(files, vec!["c.rs", "a.rs", "b.rs"]);
+
+        rank(&mut spots, Sort::Score, Some(2));
+        assert_eq!(spots.len(), 2);
+        assert_eq!(spots[0].file, "a.rs");
+        assert_eq!(spots[1].file, "b.rs");
+    }
+
+    #[test]
+    fn test_format_from_str() {
+        assert!(matches!("text".parse::<Format>().unwrap(), Format::Text));
+        assert!(matches!("json".parse::<Format>().unwrap(), Format::Json));
+        assert!(matches!("csv".parse::<Format>().unwrap(), Format::Csv));
+        assert!("bogus".parse::<Format>().is_err());
+    }
+
+    #[test]
+    fn test_color_from_str() {
+        assert!(matches!("green".parse::<Color>().unwrap(), Color::Green));
+        assert!(matches!("red".parse::<Color>().unwrap(), Color::Red));
+        assert!("bogus".parse::<Color>().is_err());
+    }
+
+    #[test]
+    fn test_sort_from_str() {
+        assert!(matches!("score".parse::<Sort>().unwrap(), Sort::Score));
+        assert!(matches!("file".parse::<Sort>().unwrap(), Sort::File));
+        assert!(matches!("fixes".parse::<Sort>().unwrap(), Sort::Fixes));
+        assert!("bogus".parse::<Sort>().is_err());
+    }
 }